@@ -1,8 +1,11 @@
+use std::fmt;
 use std::mem;
+use std::marker::PhantomData;
 
 use self::BufferedParserState::{Beginning, Middle, EndMatch, EndFail};
 use self::MatchResult::{Undecided, Committed, Matched, Failed};
 use self::ConstantParserState::{AtOffset, AtEnd};
+use self::CharacterParserState::{Pending, Done};
 
 // ----------- Types with lifetimes -------------
 
@@ -31,8 +34,42 @@ pub trait Consumer<T> where T: for<'a> TypeWithLifetime<'a> {
 
 struct DiscardConsumer;
 
-impl Consumer<Unit> for DiscardConsumer {
-    fn accept(&mut self, _: ()) {}
+impl<T> Consumer<T> for DiscardConsumer where T: for<'a> TypeWithLifetime<'a> {
+    fn accept<'a>(&mut self, _: At<'a,T>) {}
+}
+
+// ----------- Failure diagnostics ------------
+
+// What a parser wanted to see, and where it gave up. `position` is a
+// byte/element offset from the start of the overall stream: the `position`
+// the caller passed to the `push` at which the leaf parser failed, i.e. the
+// start of that pushed value rather than the exact element that mismatched.
+#[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
+pub struct Expected {
+    pub descriptions: Vec<String>,
+    pub position: usize,
+}
+
+impl Expected {
+    pub fn new(description: String, position: usize) -> Expected {
+        Expected{ descriptions: vec![description], position: position }
+    }
+
+    // Combines the expectations of two alternatives tried at the same
+    // position (e.g. the two sides of `or_else`). Whichever alternative
+    // got further into the input is the more useful diagnostic and wins
+    // outright; a tie merges both sets of descriptions.
+    pub fn merge(self, other: Expected) -> Expected {
+        if self.position > other.position {
+            self
+        } else if other.position > self.position {
+            other
+        } else {
+            let mut descriptions = self.descriptions;
+            descriptions.extend(other.descriptions);
+            Expected{ descriptions: descriptions, position: self.position }
+        }
+    }
 }
 
 // ----------- Types for parsers ------------
@@ -42,18 +79,19 @@ impl Consumer<Unit> for DiscardConsumer {
 // init -Undecided->  init
 // init -Committed->  committed
 // init -Matched(s)-> matched
-// init -Failed(b)->  failed(b)
+// init -Failed(b,e)-> failed(b)
 //
-// committed -Committed->     committed
-// committed -Matched(s)->    matched
-// committed -Failed(false)-> failed(false)
+// committed -Committed->       committed
+// committed -Matched(s)->      matched
+// committed -Failed(false,e)-> failed(false)
 //
 // matched -Matched(s)-> matched
 //
-// failed(b) -Failed(b)-> failed(b)
+// failed(b) -Failed(b,e)-> failed(b)
 //
-// The Failed(b) action carries a boolean indicating if backtracking is allowed.
-// Note that there is no transition . -Committed-> . -Failed(true)-> . so
+// The Failed(b,e) action carries a boolean indicating if backtracking is
+// allowed, and an Expected describing what the parser wanted instead.
+// Note that there is no transition . -Committed-> . -Failed(true,e)-> . so
 // once a parser has committed, we can clean up space associated with backtracking.
 
 #[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
@@ -61,15 +99,24 @@ pub enum MatchResult<T> {
     Undecided,
     Committed,
     Matched(T),
-    Failed(bool),
+    Failed(bool, Expected),
 }
 
 pub trait Parser<S,T> where S: for<'a> TypeWithLifetime<'a>, T: for<'a> TypeWithLifetime<'a> {
+    // `position` is the offset of `value`'s first element from the start of
+    // the overall stream. Callers must thread it through: it stays the same
+    // across two parsers pushed with the same `value` (e.g. either side of
+    // `or_else`, or a lookahead), and advances by however much of `value`
+    // was actually consumed when a combinator hands a shorter slice on to
+    // the next parser in a sequence (e.g. `and_then`'s rhs). Leaf parsers
+    // report it back in their `Expected` on failure, instead of keeping
+    // their own local counter.
+    //
     // If push returns Undecided or Failed(true), it is side-effect-free
     // In the case where T is "list-like" (e.g. &str or &[T])
     // push(nil) is a no-op
     // push(a ++ b) is the same as push(a); push(b)
-    fn push<'a>(&mut self, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>>;
+    fn push<'a>(&mut self, position: usize, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>>;
     // Resets the parser state back to its initial state
     // Returns true if there was a match.
     fn done(&mut self, downstream: &mut Consumer<T>) -> bool;
@@ -86,12 +133,12 @@ pub struct CommittedParser<P> {
 }
 
 impl<S,T,P> Parser<S,T> for CommittedParser<P> where P: Parser<S,T>, S: for<'a> TypeWithLifetime<'a>, T: for<'a> TypeWithLifetime<'a>  {
-    fn push<'a>(&mut self, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>> {
-        match self.parser.push(value, downstream) {
+    fn push<'a>(&mut self, position: usize, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>> {
+        match self.parser.push(position, value, downstream) {
             Undecided     => Committed,
             Committed     => Committed,
             Matched(rest) => Matched(rest),
-            Failed(_)     => Failed(false),
+            Failed(_, expected) => Failed(false, expected),
         }
     }
     fn done(&mut self, downstream: &mut Consumer<T>) -> bool {
@@ -107,32 +154,231 @@ pub struct AndThenParser<L,R> {
     in_lhs: bool,
 }
 
-impl<S,T,L,R> Parser<S,T> for AndThenParser<L,R> where L: Parser<S,T>, R: Parser<S,T>, S: for<'a> TypeWithLifetime<'a>, T: for<'a> TypeWithLifetime<'a>  {
-    fn push<'a>(&mut self, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>> {
+impl<S,T,L,R> Parser<S,T> for AndThenParser<L,R> where L: Parser<S,T>, R: Parser<S,T>, S: ListLike, for<'a> At<'a,S>: Copy, T: for<'a> TypeWithLifetime<'a>  {
+    fn push<'a>(&mut self, position: usize, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>> {
         if self.in_lhs {
-            match self.lhs.push(value, downstream) {
+            match self.lhs.push(position, value, downstream) {
                 Undecided     => Undecided,
                 Committed     => Committed,
-                Matched(rest) => { self.in_lhs = false; self.rhs.push(rest, downstream) },
-                Failed(b)     => Failed(b),
+                Matched(rest) => { self.in_lhs = false; let consumed = S::len(value) - S::len(rest); self.rhs.push(position + consumed, rest, downstream) },
+                Failed(b, e)  => Failed(b, e),
             }
         } else {
-            self.rhs.push(value, downstream)
+            self.rhs.push(position, value, downstream)
+        }
+    }
+    fn done(&mut self, downstream: &mut Consumer<T>) -> bool {
+        let result = self.lhs.done(downstream) && self.rhs.done(downstream);
+        self.in_lhs = true;
+        result
+    }
+}
+
+// ----------- Alternation ---------------
+
+#[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
+enum Side {
+    Left,
+    // `lhs` has accepted non-empty input, so there is no falling back.
+    LeftOnly,
+    Right,
+}
+
+// Tries `lhs` first, falling back to `rhs` only if `lhs` rejects the first
+// non-empty push with `Failed(true, _)`; any other answer locks in `lhs`.
+// Because `Failed(true, _)` is side-effect-free, the fallback can replay the
+// very same `value` into `rhs` without any buffering: strictly LL(1).
+pub struct OrElseParser<L,R> {
+    lhs: L,
+    rhs: R,
+    which: Side,
+    // `lhs`'s Expected, held onto across the fallback so that if `rhs` also
+    // fails at the same position, the two get merged into one diagnostic.
+    lhs_expected: Option<Expected>,
+}
+
+pub fn or_else<L,R>(lhs: L, rhs: R) -> OrElseParser<L,R> {
+    OrElseParser{ lhs: lhs, rhs: rhs, which: Side::Left, lhs_expected: None }
+}
+
+impl<S,T,L,R> Parser<S,T> for OrElseParser<L,R>
+    where L: Parser<S,T>, R: Parser<S,T>, S: ListLike, T: for<'a> TypeWithLifetime<'a>,
+          for<'a> At<'a,S>: Copy
+{
+    fn push<'a>(&mut self, position: usize, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>> {
+        match self.which {
+            Side::Left => match self.lhs.push(position, value, downstream) {
+                Undecided     => { if !S::is_empty(value) { self.which = Side::LeftOnly; } Undecided },
+                Committed     => Committed,
+                Matched(rest) => Matched(rest),
+                Failed(true, expected) => {
+                    self.which = Side::Right;
+                    self.lhs_expected = Some(expected);
+                    let result = self.rhs.push(position, value, downstream);
+                    self.merge_rhs_failure(result)
+                },
+                Failed(false, expected) => Failed(false, expected),
+            },
+            Side::LeftOnly => self.lhs.push(position, value, downstream),
+            Side::Right => { let result = self.rhs.push(position, value, downstream); self.merge_rhs_failure(result) },
+        }
+    }
+    fn done(&mut self, downstream: &mut Consumer<T>) -> bool {
+        let result = match self.which {
+            Side::Left | Side::LeftOnly => self.lhs.done(downstream),
+            Side::Right                 => self.rhs.done(downstream),
+        };
+        self.which = Side::Left;
+        self.lhs_expected = None;
+        result
+    }
+}
+
+impl<L,R> OrElseParser<L,R> {
+    fn merge_rhs_failure<T>(&mut self, result: MatchResult<T>) -> MatchResult<T> {
+        match result {
+            Failed(b, expected) => Failed(b, match self.lhs_expected.take() {
+                Some(lhs_expected) => lhs_expected.merge(expected),
+                None                => expected,
+            }),
+            other => other,
+        }
+    }
+}
+
+// ----------- Repetition ---------------
+
+// Matches the inner parser zero or more times, accumulating every item it
+// emits into `downstream`. `at_boundary` tracks whether the parser is
+// between iterations (no partial iteration in progress), which is exactly
+// when a `Failed(true)` from the inner parser means "no more repetitions"
+// rather than a real failure.
+pub struct StarParser<P> {
+    parser: P,
+    at_boundary: bool,
+}
+
+pub fn star<P>(parser: P) -> StarParser<P> {
+    StarParser{ parser: parser, at_boundary: true }
+}
+
+impl<S,T,P> Parser<S,T> for StarParser<P>
+    where P: Parser<S,T>, S: ListLike, for<'a> At<'a,S>: Copy, T: for<'a> TypeWithLifetime<'a>
+{
+    fn push<'a>(&mut self, position: usize, value: At<'a,S>, downstream: &mut Consumer<T>) -> MatchResult<At<'a,S>> {
+        // Iterative rather than recursing on every `Matched`: a naive
+        // self-recursion would blow the stack both on a long run of matches
+        // within a single `push` call, and (worse) forever, if the inner
+        // parser is zero-consuming (e.g. `star(followed_by(p))`). The
+        // `S::len(next) == S::len(rest)` check below is what
+        // detects that latter case and stops the repetition instead of
+        // looping on unconsumed input.
+        let mut position = position;
+        let mut rest = value;
+        loop {
+            match self.parser.push(position, rest, downstream) {
+                Undecided     => { if !S::is_empty(rest) { self.at_boundary = false; } return Undecided; },
+                Committed     => { self.at_boundary = false; return Committed; },
+                Matched(next) => {
+                    self.parser.done(downstream);
+                    self.at_boundary = true;
+                    if S::len(next) == S::len(rest) {
+                        return Matched(next);
+                    }
+                    position += S::len(rest) - S::len(next);
+                    rest = next;
+                },
+                Failed(true, _) if self.at_boundary => return Matched(rest),
+                Failed(b, e)  => return Failed(b, e),
+            }
         }
     }
     fn done(&mut self, downstream: &mut Consumer<T>) -> bool {
-        self.lhs.done(downstream) && self.rhs.done(downstream)
+        self.parser.done(downstream);
+        let result = self.at_boundary;
+        self.at_boundary = true;
+        result
     }
 }
 
+// Matches the inner parser one or more times: a single mandatory match
+// followed by `star` of the same parser for the rest.
+pub fn plus<P>(parser: P) -> AndThenParser<P, StarParser<P>> where P: Clone {
+    AndThenParser{ lhs: parser.clone(), rhs: CommittedParser{ parser: star(parser) }, in_lhs: true }
+}
+
 // ----------- Matching strings -------------
 
+#[derive(Clone, Copy)]
 pub struct Str;
 
 impl<'a> TypeWithLifetime<'a> for Str {
     type Type = &'a str;
 }
 
+// ----------- Matching arbitrary slices -------------
+
+pub struct Slice<T> (PhantomData<T>);
+
+impl<T> Clone for Slice<T> { fn clone(&self) -> Self { Slice(PhantomData) } }
+impl<T> Copy for Slice<T> {}
+
+impl<'a,T> TypeWithLifetime<'a> for Slice<T> {
+    type Type = &'a [T];
+}
+
+// ----------- List-like streams -------------
+
+// Factors out the handful of operations `ConstantParser`/`BufferedParser`
+// need from their input, so they can work over any element stream (e.g.
+// `Str` for UTF-8 text, `Slice<T>` for byte/token streams) instead of
+// being hard-wired to `&str`.
+pub trait ListLike: for<'a> TypeWithLifetime<'a> where for<'a> At<'a,Self>: Copy {
+    type Owned;
+    fn owned_new() -> Self::Owned;
+    fn owned_len(owned: &Self::Owned) -> usize;
+    fn owned_tail<'a>(owned: &'a Self::Owned, index: usize) -> At<'a,Self>;
+    fn owned_push<'a>(owned: &mut Self::Owned, value: At<'a,Self>);
+    fn owned_from<'a>(value: At<'a,Self>) -> Self::Owned {
+        let mut owned = Self::owned_new();
+        Self::owned_push(&mut owned, value);
+        owned
+    }
+    fn is_empty<'a>(value: At<'a,Self>) -> bool {
+        Self::len(value) == 0
+    }
+    fn len<'a>(value: At<'a,Self>) -> usize;
+    fn starts_with<'a>(value: At<'a,Self>, prefix: At<'a,Self>) -> bool;
+    fn split_at<'a>(value: At<'a,Self>, mid: usize) -> (At<'a,Self>, At<'a,Self>);
+    // A human-readable rendering of an owned value, used to build the
+    // `Expected` reported when a `ConstantParser` fails to match it.
+    fn describe(owned: &Self::Owned) -> String;
+}
+
+impl ListLike for Str {
+    type Owned = String;
+    fn owned_new() -> String { String::new() }
+    fn owned_len(owned: &String) -> usize { owned.len() }
+    fn owned_tail<'a>(owned: &'a String, index: usize) -> &'a str { &owned[index..] }
+    fn owned_push<'a>(owned: &mut String, value: &'a str) { owned.push_str(value); }
+    fn len<'a>(value: &'a str) -> usize { value.len() }
+    fn starts_with<'a>(value: &'a str, prefix: &'a str) -> bool { value.starts_with(prefix) }
+    fn split_at<'a>(value: &'a str, mid: usize) -> (&'a str, &'a str) { value.split_at(mid) }
+    fn describe(owned: &String) -> String { format!("{:?}", owned) }
+}
+
+impl<T> ListLike for Slice<T> where T: Clone + PartialEq + fmt::Debug {
+    type Owned = Vec<T>;
+    fn owned_new() -> Vec<T> { Vec::new() }
+    fn owned_len(owned: &Vec<T>) -> usize { owned.len() }
+    fn owned_tail<'a>(owned: &'a Vec<T>, index: usize) -> &'a [T] { &owned[index..] }
+    fn owned_push<'a>(owned: &mut Vec<T>, value: &'a [T]) { owned.extend_from_slice(value); }
+    fn len<'a>(value: &'a [T]) -> usize { value.len() }
+    fn starts_with<'a>(value: &'a [T], prefix: &'a [T]) -> bool { value.starts_with(prefix) }
+    fn split_at<'a>(value: &'a [T], mid: usize) -> (&'a [T], &'a [T]) { value.split_at(mid) }
+    fn describe(owned: &Vec<T>) -> String { format!("{:?}", owned) }
+}
+
 // ----------- Constant parsers -------------
 
 #[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
@@ -141,19 +387,25 @@ pub enum ConstantParserState {
     AtEnd(bool),
 }
 
-pub struct ConstantParser {
-    constant: String,
+pub struct ConstantParser<S> where S: ListLike, for<'a> At<'a,S>: Copy {
+    constant: S::Owned,
     state: ConstantParserState,
 }
 
-impl Parser<Str,Unit> for ConstantParser {
-    fn push<'a>(&mut self, string: &'a str, downstream: &mut Consumer<Unit>) -> MatchResult<&'a str> {
+impl<S> Clone for ConstantParser<S> where S: ListLike, for<'a> At<'a,S>: Copy, S::Owned: Clone {
+    fn clone(&self) -> Self {
+        ConstantParser{ constant: self.constant.clone(), state: self.state.clone() }
+    }
+}
+
+impl<S> Parser<S,Unit> for ConstantParser<S> where S: ListLike, for<'a> At<'a,S>: Copy {
+    fn push<'a>(&mut self, position: usize, string: At<'a,S>, downstream: &mut Consumer<Unit>) -> MatchResult<At<'a,S>> {
         match self.state {
-            AtOffset(index) if string.starts_with(&self.constant[index..]) => { downstream.accept(()); self.state = AtEnd(true); Matched(&string[(self.constant.len() - index)..]) },
-            AtOffset(index) if self.constant[index..].starts_with(string)  => { self.state = AtOffset(index + string.len()); Undecided },
-            AtOffset(_)                                                    => { self.state = AtEnd(false); Failed(true) },
-            AtEnd(true)                                                    => { Matched(string) },            
-            AtEnd(false)                                                   => { Failed(true) },
+            AtOffset(index) if S::starts_with(string, S::owned_tail(&self.constant, index)) => { downstream.accept(()); self.state = AtEnd(true); Matched(S::split_at(string, S::owned_len(&self.constant) - index).1) },
+            AtOffset(index) if S::starts_with(S::owned_tail(&self.constant, index), string)  => { self.state = AtOffset(index + S::len(string)); Undecided },
+            AtOffset(_)                                                                      => { self.state = AtEnd(false); Failed(true, Expected::new(S::describe(&self.constant), position)) },
+            AtEnd(true)                                                                      => { Matched(string) },
+            AtEnd(false)                                                                     => { Failed(true, Expected::new(S::describe(&self.constant), position)) },
         }
     }
     fn done(&mut self, _: &mut Consumer<Unit>) -> bool {
@@ -163,75 +415,427 @@ impl Parser<Str,Unit> for ConstantParser {
     }
 }
 
-pub fn constant(string: String) -> ConstantParser {
-    ConstantParser{ constant: string, state: AtOffset(0) }
+pub fn constant<S>(value: S::Owned) -> ConstantParser<S> where S: ListLike, for<'a> At<'a,S>: Copy {
+    ConstantParser{ constant: value, state: AtOffset(0) }
 }
 
-// If m is a Parser<Str,Unit> then m.buffer() is a Parser<Str,Str>.
+// If m is a Parser<S,Unit> then m.buffer() is a Parser<S,S>.
 // It does as little buffering as it can, but it does allocate as buffer for the case
 // where the boundary marker of the input is misaligned with that of the parser.
 // For example, m is matching string literals, and the input is '"abc' followed by 'def"'
 // we have to buffer up '"abc'.
 
-#[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
-enum BufferedParserState {
+enum BufferedParserState<S> where S: ListLike, for<'a> At<'a,S>: Copy {
     Beginning,
-    Middle(String),
+    Middle(S::Owned),
     EndMatch,
-    EndFail(bool),
+    EndFail(bool, Expected),
 }
 
-pub struct BufferedParser<P> {
+pub struct BufferedParser<S,P> where S: ListLike, for<'a> At<'a,S>: Copy {
     parser: P,
-    state: BufferedParserState,
+    state: BufferedParserState<S>,
 }
 
-impl<P> Parser<Str,Str> for BufferedParser<P> where P: Parser<Str,Unit> {
-    fn push<'a>(&mut self, string: &'a str, downstream: &mut Consumer<Str>) -> MatchResult<&'a str> {
+impl<S,P> Parser<S,S> for BufferedParser<S,P> where S: ListLike, for<'a> At<'a,S>: Copy, P: Parser<S,Unit> {
+    fn push<'a>(&mut self, position: usize, string: At<'a,S>, downstream: &mut Consumer<S>) -> MatchResult<At<'a,S>> {
         match mem::replace(&mut self.state, EndMatch) {
             Beginning => {
-                let result = self.parser.push(string, &mut DiscardConsumer);
+                let result = self.parser.push(position, string, &mut DiscardConsumer);
                 match result {
-                    Undecided     => self.state = Middle(String::from(string)),
-                    Committed     => self.state = Middle(String::from(string)),
-                    Failed(b)     => self.state = EndFail(b),
-                    Matched(rest) => downstream.accept(&string[..(string.len()-rest.len())]),
+                    Undecided         => self.state = Middle(S::owned_from(string)),
+                    Committed         => self.state = Middle(S::owned_from(string)),
+                    Failed(b, ref e)  => self.state = EndFail(b, e.clone()),
+                    Matched(rest)     => downstream.accept(S::split_at(string, S::len(string) - S::len(rest)).0),
                 }
                 result
             },
             Middle(mut buffer) => {
-                let result = self.parser.push(string, &mut DiscardConsumer);
+                let result = self.parser.push(position, string, &mut DiscardConsumer);
                 match result {
-                    Undecided     => { buffer.push_str(string); self.state = Middle(buffer); },
-                    Committed     => { buffer.push_str(string); self.state = Middle(buffer); },
-                    Failed(b)     => { self.state = EndFail(b); },
-                    Matched(rest) => { buffer.push_str(&string[..(string.len()-rest.len())]); downstream.accept(&*buffer); },
+                    Undecided        => { S::owned_push(&mut buffer, string); self.state = Middle(buffer); },
+                    Committed        => { S::owned_push(&mut buffer, string); self.state = Middle(buffer); },
+                    Failed(b, ref e) => { self.state = EndFail(b, e.clone()); },
+                    Matched(rest)    => { S::owned_push(&mut buffer, S::split_at(string, S::len(string) - S::len(rest)).0); downstream.accept(S::owned_tail(&buffer, 0)); },
                 }
                 result
             }
             EndMatch => Matched(string),
-            EndFail(b) => Failed(b),
+            EndFail(b, e) => Failed(b, e),
         }
     }
-    fn done(&mut self, downstream: &mut Consumer<Str>) -> bool {
+    fn done(&mut self, downstream: &mut Consumer<S>) -> bool {
         let result = self.parser.done(&mut DiscardConsumer);
-        if result { if let Middle(ref buffer) = self.state { downstream.accept(&*buffer) } }
+        if result { if let Middle(ref buffer) = self.state { downstream.accept(S::owned_tail(buffer, 0)) } }
         self.state = Beginning;
         result
     }
 }
 
+// ----------- Matching single characters -------------
+
+#[derive(Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
+pub enum CharacterParserState {
+    Pending,
+    Done(bool),
+}
+
+pub struct CharacterParser<F> {
+    predicate: F,
+    state: CharacterParserState,
+}
+
+fn character_expected(position: usize) -> Expected {
+    Expected::new(String::from("a character satisfying the predicate"), position)
+}
+
+impl<F> Parser<Str,Str> for CharacterParser<F> where F: Fn(char) -> bool {
+    fn push<'a>(&mut self, position: usize, string: &'a str, downstream: &mut Consumer<Str>) -> MatchResult<&'a str> {
+        match self.state {
+            Pending => match string.chars().next() {
+                Some(c) if (self.predicate)(c) => {
+                    let len = c.len_utf8();
+                    downstream.accept(&string[..len]);
+                    self.state = Done(true);
+                    Matched(&string[len..])
+                },
+                Some(_) => { self.state = Done(false); Failed(true, character_expected(position)) },
+                None    => Undecided,
+            },
+            Done(true)  => Matched(string),
+            Done(false) => Failed(true, character_expected(position)),
+        }
+    }
+    fn done(&mut self, _: &mut Consumer<Str>) -> bool {
+        let result = self.state == Done(true);
+        self.state = Pending;
+        result
+    }
+}
+
+pub fn character<F>(predicate: F) -> CharacterParser<F> where F: Fn(char) -> bool {
+    CharacterParser{ predicate: predicate, state: Pending }
+}
+
+// ----------- Lookahead ---------------
+
+// Runs `parser` purely to decide success or failure, then rewinds: no
+// input is ever consumed and nothing is emitted downstream, only the
+// original pushed string is handed back unconsumed. Reuses the
+// `BufferedParserState` buffering from `BufferedParser` so a decision
+// that only resolves after several chunks still works.
+pub struct LookaheadParser<P> {
+    parser: P,
+    negate: bool,
+    state: BufferedParserState<Str>,
+}
+
+fn lookahead<P>(parser: P, negate: bool) -> LookaheadParser<P> {
+    LookaheadParser{ parser: parser, negate: negate, state: Beginning }
+}
+
+fn negated_lookahead_expected(position: usize) -> Expected {
+    Expected::new(String::from("input not matching the lookahead"), position)
+}
+
+pub fn followed_by<P>(parser: P) -> LookaheadParser<P> {
+    lookahead(parser, false)
+}
+
+pub fn not_followed_by<P>(parser: P) -> LookaheadParser<P> {
+    lookahead(parser, true)
+}
+
+impl<T,P> Parser<Str,T> for LookaheadParser<P> where P: Parser<Str,Unit>, T: for<'a> TypeWithLifetime<'a> {
+    fn push<'a>(&mut self, position: usize, string: &'a str, _downstream: &mut Consumer<T>) -> MatchResult<&'a str> {
+        match mem::replace(&mut self.state, EndMatch) {
+            Beginning => match self.parser.push(position, string, &mut DiscardConsumer) {
+                Undecided | Committed => { self.state = Middle(String::from(string)); Undecided },
+                Matched(_)            => if self.negate { let e = negated_lookahead_expected(position); self.state = EndFail(true, e.clone()); Failed(true, e) } else { Matched(string) },
+                Failed(_, e)          => if self.negate { Matched(string) } else { self.state = EndFail(true, e.clone()); Failed(true, e) },
+            },
+            Middle(mut buffer) => match self.parser.push(position, string, &mut DiscardConsumer) {
+                Undecided | Committed => { buffer.push_str(string); self.state = Middle(buffer); Undecided },
+                Matched(_)            => if self.negate { let e = negated_lookahead_expected(position); self.state = EndFail(true, e.clone()); Failed(true, e) } else { Matched(string) },
+                Failed(_, e)          => if self.negate { Matched(string) } else { self.state = EndFail(true, e.clone()); Failed(true, e) },
+            },
+            EndMatch      => Matched(string),
+            EndFail(b, e) => Failed(b, e),
+        }
+    }
+    fn done(&mut self, _: &mut Consumer<T>) -> bool {
+        let result = match self.state { EndMatch => true, _ => false };
+        self.parser.done(&mut DiscardConsumer);
+        self.state = Beginning;
+        result
+    }
+}
+
+// ----------- Mapping emitted output ---------------
+
+// `F: for<'a> FnMut(At<'a,T>) -> At<'a,U>` can't be satisfied by ordinary
+// closures when `U` is a free type parameter: rustc has no way to check,
+// for a generic `U`, that the output doesn't itself depend on the bound
+// lifetime `'a` (E0582). So `map`/`filter_map` only support transforming
+// into lifetime-independent output (an `Always<X>`), which is also the
+// realistic use case (e.g. turning a matched `&str` into a `u32`) — the
+// closure's signature `for<'a> FnMut(At<'a,T>) -> X` is then perfectly
+// ordinary, since `X` never mentions `'a`.
+
+// Adapts a `Consumer<Always<X>>` into a `Consumer<T>` by running every
+// accepted item through `f` first. Built fresh inside each `push`/`done`
+// call so the borrow of `f` lines up with whatever call is in progress.
+struct MapConsumer<'c, T, X, F: 'c> {
+    downstream: &'c mut Consumer<Always<X>>,
+    f: &'c mut F,
+    marker: PhantomData<T>,
+}
+
+impl<'c, T, X, F> Consumer<T> for MapConsumer<'c, T, X, F>
+    where T: for<'a> TypeWithLifetime<'a>,
+          F: for<'a> FnMut(At<'a,T>) -> X
+{
+    fn accept<'a>(&mut self, arg: At<'a,T>) {
+        self.downstream.accept((self.f)(arg));
+    }
+}
+
+pub struct MapParser<P,F,T> {
+    parser: P,
+    f: F,
+    marker: PhantomData<T>,
+}
+
+pub fn map<P,F,T>(parser: P, f: F) -> MapParser<P,F,T> {
+    MapParser{ parser: parser, f: f, marker: PhantomData }
+}
+
+impl<S,T,X,P,F> Parser<S,Always<X>> for MapParser<P,F,T>
+    where P: Parser<S,T>, S: for<'a> TypeWithLifetime<'a>, T: for<'a> TypeWithLifetime<'a>,
+          F: for<'a> FnMut(At<'a,T>) -> X
+{
+    fn push<'a>(&mut self, position: usize, value: At<'a,S>, downstream: &mut Consumer<Always<X>>) -> MatchResult<At<'a,S>> {
+        let mut adapter = MapConsumer{ downstream: downstream, f: &mut self.f, marker: PhantomData };
+        self.parser.push(position, value, &mut adapter)
+    }
+    fn done(&mut self, downstream: &mut Consumer<Always<X>>) -> bool {
+        let mut adapter = MapConsumer{ downstream: downstream, f: &mut self.f, marker: PhantomData };
+        self.parser.done(&mut adapter)
+    }
+}
+
+// Like `MapConsumer`, but `f` can decline to forward an item at all.
+struct FilterMapConsumer<'c, T, X, F: 'c> {
+    downstream: &'c mut Consumer<Always<X>>,
+    f: &'c mut F,
+    marker: PhantomData<T>,
+}
+
+impl<'c, T, X, F> Consumer<T> for FilterMapConsumer<'c, T, X, F>
+    where T: for<'a> TypeWithLifetime<'a>,
+          F: for<'a> FnMut(At<'a,T>) -> Option<X>
+{
+    fn accept<'a>(&mut self, arg: At<'a,T>) {
+        if let Some(result) = (self.f)(arg) {
+            self.downstream.accept(result);
+        }
+    }
+}
+
+pub struct FilterMapParser<P,F,T> {
+    parser: P,
+    f: F,
+    marker: PhantomData<T>,
+}
+
+pub fn filter_map<P,F,T>(parser: P, f: F) -> FilterMapParser<P,F,T> {
+    FilterMapParser{ parser: parser, f: f, marker: PhantomData }
+}
+
+impl<S,T,X,P,F> Parser<S,Always<X>> for FilterMapParser<P,F,T>
+    where P: Parser<S,T>, S: for<'a> TypeWithLifetime<'a>, T: for<'a> TypeWithLifetime<'a>,
+          F: for<'a> FnMut(At<'a,T>) -> Option<X>
+{
+    fn push<'a>(&mut self, position: usize, value: At<'a,S>, downstream: &mut Consumer<Always<X>>) -> MatchResult<At<'a,S>> {
+        let mut adapter = FilterMapConsumer{ downstream: downstream, f: &mut self.f, marker: PhantomData };
+        self.parser.push(position, value, &mut adapter)
+    }
+    fn done(&mut self, downstream: &mut Consumer<Always<X>>) -> bool {
+        let mut adapter = FilterMapConsumer{ downstream: downstream, f: &mut self.f, marker: PhantomData };
+        self.parser.done(&mut adapter)
+    }
+}
+
+// `Expected` is hard to predict exactly at every call site, so tests that
+// only care whether a parser backtracked use this helper instead of
+// comparing the full `MatchResult` with `assert_eq!`.
+fn assert_failed<T>(result: MatchResult<T>, backtrack: bool) {
+    match result {
+        Failed(b, _) => assert_eq!(b, backtrack),
+        _            => panic!("expected Failed({}, _), got something else", backtrack),
+    }
+}
+
 #[test]
 fn test_constant() {
-    let mut parser = constant(String::from("abc"));
+    let mut parser = constant::<Str>(String::from("abc"));
     assert_eq!(parser.done(&mut DiscardConsumer), false);
-    assert_eq!(parser.push("fred", &mut DiscardConsumer), Failed(true));
+    assert_failed(parser.push(0, "fred", &mut DiscardConsumer), true);
     assert_eq!(parser.done(&mut DiscardConsumer), false);
-    assert_eq!(parser.push("abcdef", &mut DiscardConsumer), Matched("def"));
+    assert_eq!(parser.push(0, "abcdef", &mut DiscardConsumer), Matched("def"));
     assert_eq!(parser.done(&mut DiscardConsumer), true);
-    assert_eq!(parser.push("a", &mut DiscardConsumer), Undecided);
+    assert_eq!(parser.push(0, "a", &mut DiscardConsumer), Undecided);
     assert_eq!(parser.done(&mut DiscardConsumer), false);
-    assert_eq!(parser.push("ab", &mut DiscardConsumer), Undecided);
-    assert_eq!(parser.push("cd", &mut DiscardConsumer), Matched("d"));
+    assert_eq!(parser.push(0, "ab", &mut DiscardConsumer), Undecided);
+    assert_eq!(parser.push(2, "cd", &mut DiscardConsumer), Matched("d"));
     assert_eq!(parser.done(&mut DiscardConsumer), true);
 }
+
+#[test]
+fn test_star_and_plus() {
+    let mut zero_or_more = star(constant::<Str>(String::from("ab")));
+    assert_eq!(zero_or_more.push(0, "ababxy", &mut DiscardConsumer), Matched("xy"));
+    assert_eq!(zero_or_more.done(&mut DiscardConsumer), true);
+    assert_eq!(zero_or_more.push(0, "xy", &mut DiscardConsumer), Matched("xy"));
+    assert_eq!(zero_or_more.done(&mut DiscardConsumer), true);
+
+    let mut one_or_more = plus(constant::<Str>(String::from("ab")));
+    assert_eq!(one_or_more.push(0, "ababxy", &mut DiscardConsumer), Matched("xy"));
+    assert_eq!(one_or_more.done(&mut DiscardConsumer), true);
+
+    let mut one_or_more = plus(constant::<Str>(String::from("ab")));
+    assert_failed(one_or_more.push(0, "xy", &mut DiscardConsumer), true);
+}
+
+#[test]
+fn test_or_else() {
+    let mut parser = or_else(constant::<Str>(String::from("cat")), constant::<Str>(String::from("dog")));
+    assert_eq!(parser.push(0, "cats", &mut DiscardConsumer), Matched("s"));
+    assert_eq!(parser.done(&mut DiscardConsumer), true);
+
+    let mut parser = or_else(constant::<Str>(String::from("cat")), constant::<Str>(String::from("dog")));
+    assert_eq!(parser.push(0, "dogs", &mut DiscardConsumer), Matched("s"));
+    assert_eq!(parser.done(&mut DiscardConsumer), true);
+
+    let mut parser = or_else(constant::<Str>(String::from("cat")), constant::<Str>(String::from("dog")));
+    assert_failed(parser.push(0, "fish", &mut DiscardConsumer), true);
+}
+
+#[test]
+fn test_character() {
+    let mut parser = character(char::is_alphanumeric);
+    assert_eq!(parser.push(0, "a1", &mut DiscardConsumer), Matched("1"));
+    assert_eq!(parser.done(&mut DiscardConsumer), true);
+
+    let mut parser = character(char::is_alphanumeric);
+    assert_failed(parser.push(0, " a", &mut DiscardConsumer), true);
+    assert_eq!(parser.done(&mut DiscardConsumer), false);
+
+    let mut parser = character(char::is_alphanumeric);
+    assert_eq!(parser.push(0, "", &mut DiscardConsumer), Undecided);
+    assert_eq!(parser.push(0, "9", &mut DiscardConsumer), Matched(""));
+}
+
+#[test]
+fn test_lookahead() {
+    let mut parser = followed_by(constant::<Str>(String::from("ab")));
+    assert_eq!(parser.push(0, "abc", &mut DiscardConsumer), Matched("abc"));
+    assert_eq!(parser.done(&mut DiscardConsumer), true);
+
+    let mut parser = followed_by(constant::<Str>(String::from("ab")));
+    assert_failed(parser.push(0, "xyz", &mut DiscardConsumer), true);
+
+    let mut parser = not_followed_by(constant::<Str>(String::from("ab")));
+    assert_eq!(parser.push(0, "xyz", &mut DiscardConsumer), Matched("xyz"));
+    assert_eq!(parser.done(&mut DiscardConsumer), true);
+
+    let mut parser = not_followed_by(constant::<Str>(String::from("ab")));
+    assert_failed(parser.push(0, "abc", &mut DiscardConsumer), true);
+}
+
+#[test]
+fn test_expected_diagnostics() {
+    let mut parser = constant::<Str>(String::from("abc"));
+    match parser.push(0, "xyz", &mut DiscardConsumer) {
+        Failed(true, expected) => {
+            assert_eq!(expected.descriptions, vec![String::from("\"abc\"")]);
+            assert_eq!(expected.position, 0);
+        },
+        _ => panic!("expected a failure"),
+    }
+
+    let mut parser = constant::<Str>(String::from("abc"));
+    assert_eq!(parser.push(0, "ab", &mut DiscardConsumer), Undecided);
+    match parser.push(2, "xyz", &mut DiscardConsumer) {
+        Failed(true, expected) => {
+            assert_eq!(expected.descriptions, vec![String::from("\"abc\"")]);
+            assert_eq!(expected.position, 2);
+        },
+        _ => panic!("expected a failure"),
+    }
+
+    let mut parser = or_else(constant::<Str>(String::from("cat")), constant::<Str>(String::from("dog")));
+    match parser.push(0, "fish", &mut DiscardConsumer) {
+        Failed(true, expected) => {
+            assert_eq!(expected.descriptions, vec![String::from("\"cat\""), String::from("\"dog\"")]);
+            assert_eq!(expected.position, 0);
+        },
+        _ => panic!("expected a failure"),
+    }
+
+    // The rhs of a sequence must see the real stream offset, not offset 0.
+    let mut parser = AndThenParser{ lhs: constant::<Str>(String::from("ab")), rhs: CommittedParser{ parser: constant::<Str>(String::from("cd")) }, in_lhs: true };
+    match parser.push(0, "abxy", &mut DiscardConsumer) {
+        Failed(_, expected) => assert_eq!(expected.position, 2),
+        _ => panic!("expected a failure"),
+    }
+}
+
+#[test]
+fn test_constant_over_bytes() {
+    let magic: &[u8] = &[0x00, 0x61, 0x73, 0x6d];
+    let mut parser = constant::<Slice<u8>>(Vec::from(magic));
+    assert_eq!(parser.done(&mut DiscardConsumer), false);
+    assert_eq!(parser.push(0, &[0x00, 0x61, 0x73, 0x6d, 0x01][..], &mut DiscardConsumer), Matched(&[0x01][..]));
+    assert_eq!(parser.done(&mut DiscardConsumer), true);
+    assert_eq!(parser.push(0, &[0x00, 0x61][..], &mut DiscardConsumer), Undecided);
+    assert_eq!(parser.push(2, &[0x73, 0x6d][..], &mut DiscardConsumer), Matched(&[][..]));
+    assert_eq!(parser.done(&mut DiscardConsumer), true);
+}
+
+// A consumer that just collects every item it's given, for tests that
+// need to inspect what was emitted rather than only whether a match
+// occurred.
+struct VecConsumer<T> {
+    items: Vec<T>,
+}
+
+impl<T> Consumer<Always<T>> for VecConsumer<T> {
+    fn accept(&mut self, arg: T) {
+        self.items.push(arg);
+    }
+}
+
+#[test]
+fn test_map() {
+    let mut parser = map(character(char::is_numeric), |s: &str| s.parse::<i32>().unwrap());
+    let mut consumer = VecConsumer{ items: Vec::new() };
+    assert_eq!(parser.push(0, "7x", &mut consumer), Matched("x"));
+    assert_eq!(parser.done(&mut consumer), true);
+    assert_eq!(consumer.items, vec![7]);
+}
+
+#[test]
+fn test_filter_map() {
+    let mut parser = filter_map(character(char::is_alphanumeric), |s: &str| s.chars().next().unwrap().to_digit(10));
+    let mut consumer = VecConsumer{ items: Vec::new() };
+    assert_eq!(parser.push(0, "7x", &mut consumer), Matched("x"));
+    assert_eq!(parser.done(&mut consumer), true);
+    assert_eq!(consumer.items, vec![7]);
+
+    let mut parser = filter_map(character(char::is_alphanumeric), |s: &str| s.chars().next().unwrap().to_digit(10));
+    let mut consumer = VecConsumer{ items: Vec::new() };
+    assert_eq!(parser.push(0, "xy", &mut consumer), Matched("y"));
+    assert_eq!(parser.done(&mut consumer), true);
+    assert_eq!(consumer.items, Vec::<u32>::new());
+}